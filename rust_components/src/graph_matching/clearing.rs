@@ -0,0 +1,267 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::HashSet;
+
+use super::TradeLoop;
+
+/// Above this many candidates, branch-and-bound is skipped in favor of the
+/// greedy baseline even when `exact` is requested, since the search is
+/// exponential in the worst case.
+const EXACT_CANDIDATE_LIMIT: usize = 20;
+
+fn objective_weight(objective: &str) -> PyResult<fn(&TradeLoop) -> f64> {
+    match objective {
+        "total_watch_value" => Ok(|l: &TradeLoop| l.total_watch_value),
+        "value_efficiency" => Ok(|l: &TradeLoop| l.value_efficiency),
+        "users_served" => Ok(|l: &TradeLoop| l.users.len() as f64),
+        other => Err(PyValueError::new_err(format!(
+            "unknown clearing objective: {other}"
+        ))),
+    }
+}
+
+/// Select a subset of candidate `TradeLoop`s that share no user while
+/// maximizing the summed `objective`, since in a real clearing round each
+/// user can only execute one trade. Returns the cleared disjoint set, the
+/// users left unmatched, and whether the exact solver actually ran.
+///
+/// `objective` is one of `"total_watch_value"`, `"value_efficiency"`, or
+/// `"users_served"`. `exact` requests the branch-and-bound solver, which
+/// only runs below `EXACT_CANDIDATE_LIMIT` candidates and otherwise falls
+/// back to the greedy baseline — the third return value tells the caller
+/// which one actually ran, since a caller who asked for the optimal set has
+/// no other way to tell they silently got an approximation instead.
+#[pyfunction]
+pub fn clear_loops(
+    candidates: Vec<TradeLoop>,
+    objective: &str,
+    exact: bool,
+) -> PyResult<(Vec<TradeLoop>, Vec<String>, bool)> {
+    let weight = objective_weight(objective)?;
+    let all_users: HashSet<String> = candidates
+        .iter()
+        .flat_map(|c| c.users.iter().cloned())
+        .collect();
+
+    let used_exact = exact && candidates.len() <= EXACT_CANDIDATE_LIMIT;
+    let selected = if used_exact {
+        clear_loops_exact(candidates, weight)
+    } else {
+        clear_loops_greedy(candidates, weight)
+    };
+
+    let matched_users: HashSet<String> = selected
+        .iter()
+        .flat_map(|c| c.users.iter().cloned())
+        .collect();
+    let mut unmatched: Vec<String> = all_users.difference(&matched_users).cloned().collect();
+    unmatched.sort();
+
+    Ok((selected, unmatched, used_exact))
+}
+
+/// Sort candidates by `objective` descending, accepting a cycle only if all
+/// its users are still unmatched.
+fn clear_loops_greedy(mut candidates: Vec<TradeLoop>, weight: fn(&TradeLoop) -> f64) -> Vec<TradeLoop> {
+    candidates.sort_by(|a, b| weight(b).partial_cmp(&weight(a)).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut used = HashSet::new();
+    let mut selected = Vec::new();
+    for candidate in candidates {
+        if candidate.users.iter().all(|u| !used.contains(u)) {
+            used.extend(candidate.users.iter().cloned());
+            selected.push(candidate);
+        }
+    }
+    selected
+}
+
+/// Exact maximum-weight disjoint set via branch-and-bound, pruning on the
+/// remaining achievable weight (the sum of all not-yet-decided candidates,
+/// which ignores conflicts and so is always an upper bound).
+fn clear_loops_exact(candidates: Vec<TradeLoop>, weight: fn(&TradeLoop) -> f64) -> Vec<TradeLoop> {
+    let mut order: Vec<usize> = (0..candidates.len()).collect();
+    order.sort_by(|&a, &b| {
+        weight(&candidates[b])
+            .partial_cmp(&weight(&candidates[a]))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let n = order.len();
+    let mut suffix_sum = vec![0.0; n + 1];
+    for i in (0..n).rev() {
+        suffix_sum[i] = suffix_sum[i + 1] + weight(&candidates[order[i]]);
+    }
+
+    let mut used: HashSet<String> = HashSet::new();
+    let mut current: Vec<usize> = Vec::new();
+    let mut best_value = 0.0f64;
+    let mut best_selection: Vec<usize> = Vec::new();
+
+    clear_loops_bnb(
+        0,
+        &order,
+        &candidates,
+        weight,
+        &suffix_sum,
+        &mut used,
+        &mut current,
+        0.0,
+        &mut best_value,
+        &mut best_selection,
+    );
+
+    let keep: HashSet<usize> = best_selection.into_iter().collect();
+    candidates
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| keep.contains(i))
+        .map(|(_, c)| c)
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn clear_loops_bnb(
+    idx: usize,
+    order: &[usize],
+    candidates: &[TradeLoop],
+    weight: fn(&TradeLoop) -> f64,
+    suffix_sum: &[f64],
+    used: &mut HashSet<String>,
+    current: &mut Vec<usize>,
+    current_value: f64,
+    best_value: &mut f64,
+    best_selection: &mut Vec<usize>,
+) {
+    if current_value > *best_value {
+        *best_value = current_value;
+        *best_selection = current.clone();
+    }
+
+    if idx == order.len() || current_value + suffix_sum[idx] <= *best_value {
+        return;
+    }
+
+    // Skip this candidate.
+    clear_loops_bnb(
+        idx + 1,
+        order,
+        candidates,
+        weight,
+        suffix_sum,
+        used,
+        current,
+        current_value,
+        best_value,
+        best_selection,
+    );
+
+    // Take this candidate, if it shares no user with what's already chosen.
+    let candidate = &candidates[order[idx]];
+    if candidate.users.iter().all(|u| !used.contains(u)) {
+        for u in &candidate.users {
+            used.insert(u.clone());
+        }
+        current.push(order[idx]);
+
+        clear_loops_bnb(
+            idx + 1,
+            order,
+            candidates,
+            weight,
+            suffix_sum,
+            used,
+            current,
+            current_value + weight(candidate),
+            best_value,
+            best_selection,
+        );
+
+        current.pop();
+        for u in &candidate.users {
+            used.remove(u);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(users: &[&str], total_watch_value: f64) -> TradeLoop {
+        TradeLoop {
+            loop_type: "test".to_string(),
+            indexes: (0..users.len()).collect(),
+            users: users.iter().map(|u| u.to_string()).collect(),
+            watches: users.iter().map(|u| format!("{u}-watch")).collect(),
+            values: vec![0.0; users.len()],
+            cash_flows: vec![0.0; users.len()],
+            total_watch_value,
+            total_cash_flow: 0.0,
+            value_efficiency: 1.0,
+        }
+    }
+
+    /// Loop A (users a,b) outweighs either of loops B (a,c) and C (b,d) on
+    /// its own, but A conflicts with both while B and C don't conflict with
+    /// each other — so greedy, which always takes the single best-weighted
+    /// loop first, gets stuck at A while the true optimum is B+C.
+    fn conflicting_candidates() -> Vec<TradeLoop> {
+        vec![
+            candidate(&["a", "b"], 100.0),
+            candidate(&["a", "c"], 60.0),
+            candidate(&["b", "d"], 60.0),
+        ]
+    }
+
+    #[test]
+    fn greedy_gets_stuck_on_locally_best_pick_but_exact_finds_the_optimum() {
+        let (greedy, greedy_unmatched, used_exact) =
+            clear_loops(conflicting_candidates(), "total_watch_value", false).unwrap();
+        assert!(!used_exact);
+        let greedy_total: f64 = greedy.iter().map(|c| c.total_watch_value).sum();
+        assert_eq!(greedy_total, 100.0);
+        assert_eq!(greedy_unmatched, vec!["c".to_string(), "d".to_string()]);
+
+        let (exact, exact_unmatched, used_exact) =
+            clear_loops(conflicting_candidates(), "total_watch_value", true).unwrap();
+        assert!(used_exact);
+        let exact_total: f64 = exact.iter().map(|c| c.total_watch_value).sum();
+        assert_eq!(exact_total, 120.0);
+        assert!(exact_unmatched.is_empty());
+        assert!(exact_total > greedy_total);
+    }
+
+    /// `conflicting_candidates()` plus enough disjoint singleton-user
+    /// padding loops to push the total past `EXACT_CANDIDATE_LIMIT`, so
+    /// `exact: true` is forced to downgrade regardless of the genuinely
+    /// conflicting trio among them.
+    fn candidates_above_the_exact_limit() -> Vec<TradeLoop> {
+        let mut candidates = conflicting_candidates();
+        let mut pad_users: Vec<String> = Vec::new();
+        while candidates.len() <= EXACT_CANDIDATE_LIMIT {
+            pad_users.push(format!("pad{}", candidates.len()));
+            let label = pad_users.last().unwrap().as_str();
+            candidates.push(candidate(&[label], 1.0));
+        }
+        candidates
+    }
+
+    #[test]
+    fn exact_above_the_candidate_limit_falls_back_to_greedy() {
+        let candidates = candidates_above_the_exact_limit();
+        assert!(candidates.len() > EXACT_CANDIDATE_LIMIT);
+
+        let (requested_exact, _, used_exact) =
+            clear_loops(candidates_above_the_exact_limit(), "total_watch_value", true).unwrap();
+        assert!(!used_exact, "exact should silently downgrade above EXACT_CANDIDATE_LIMIT");
+
+        let (greedy, _, _) = clear_loops(candidates, "total_watch_value", false).unwrap();
+        let requested_total: f64 = requested_exact.iter().map(|c| c.total_watch_value).sum();
+        let greedy_total: f64 = greedy.iter().map(|c| c.total_watch_value).sum();
+        assert_eq!(
+            requested_total, greedy_total,
+            "above the candidate limit, exact: true should match the greedy fallback, not the true optimum"
+        );
+    }
+}
@@ -1,10 +1,45 @@
 use pyo3::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use rand::seq::SliceRandom;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use parking_lot::Mutex;
 use rayon::prelude::*;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub mod clearing;
+pub mod order_book;
+pub use clearing::clear_loops;
+pub use order_book::{Order, OrderBook};
+
+/// A partial path sitting in the shared work-queue, ranked by the
+/// `value_efficiency` it would have if closed right now (see
+/// `path_priority`).
+#[derive(Debug, Clone)]
+struct WorkItem {
+    path: Vec<usize>,
+    priority: f64,
+}
+
+impl PartialEq for WorkItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+impl Eq for WorkItem {}
+
+impl PartialOrd for WorkItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for WorkItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.partial_cmp(&other.priority).unwrap_or(Ordering::Equal)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trade {
     pub user_id: String,
     pub have_watch: String,
@@ -67,21 +102,158 @@ impl TradeGraph {
         }
     }
 
-    fn find_loops(&self, max_loops: usize) -> Vec<TradeLoop> {
+    /// Enumerate elementary trade cycles up to `max_len` users long using
+    /// Johnson's algorithm, stopping once `max_loops` have been found.
+    ///
+    /// Unlike the old fixed 2-way/3-way search, this is exact and
+    /// deterministic at every graph size: no sampling fallback, no cap on
+    /// cycle length other than `max_len`.
+    fn find_loops(&self, max_loops: usize, max_len: usize) -> Vec<TradeLoop> {
+        let n = self.trades.len();
         let mut loops = Vec::new();
-        let mut rng = rand::thread_rng();
-        
-        // Find 2-way loops
-        self.find_two_way_loops(&mut loops, max_loops / 2);
-        
-        // If we have space for more loops, find 3-way loops
-        if loops.len() < max_loops {
-            self.find_three_way_loops(&mut loops, max_loops - loops.len());
-        }
-        
+        let mut blocked = vec![false; n];
+        let mut b: Vec<HashSet<usize>> = vec![HashSet::new(); n];
+        let mut stack: Vec<usize> = Vec::new();
+
+        for s in 0..n {
+            if loops.len() >= max_loops {
+                break;
+            }
+            for v in s..n {
+                blocked[v] = false;
+                b[v].clear();
+            }
+            self.circuit(s, s, max_len, max_loops, &mut blocked, &mut b, &mut stack, &mut loops);
+        }
+
         loops
     }
 
+    /// Priority-driven alternative to `find_loops`: a shared work-queue of
+    /// partial paths is expanded by `threads` workers in parallel, always
+    /// extending the highest-`value_efficiency`-potential path next, so the
+    /// best cycles under the `max_loops` budget surface first.
+    ///
+    /// `dynamic_batch` resizes each worker's pop batch to roughly
+    /// `heap_len / threads` instead of the fixed `batch` size, to keep
+    /// workers balanced as the queue drains unevenly. `no_stats` skips the
+    /// mutex-guarded expansion counter in the hot loop; the counter is
+    /// returned alongside the loops (always `0` when `no_stats` is set)
+    /// rather than discarded, since it has no other way to reach the caller.
+    ///
+    /// Unlike `find_loops`, searches are seeded from every vertex with no
+    /// Johnson's-style `w >= s` restriction, so the same elementary cycle can
+    /// be found starting from more than one of its vertices. A seen-set
+    /// keyed on each cycle's canonical rotation (see `canonical_rotation`)
+    /// collapses those rotations before they're counted against
+    /// `max_loops`, so the budget is spent on distinct opportunities rather
+    /// than copies of one loop. Canonicalizing on a rotation rather than a
+    /// sorted vertex set preserves edge direction, so two distinct cycles
+    /// that happen to share a vertex set (e.g. `0-1-2-0` and `0-2-1-0`) are
+    /// still counted separately.
+    ///
+    /// A full budget does not stop the search: filling a slot with the
+    /// first cycle a worker happens to close would make the result depend
+    /// on thread scheduling rather than priority once `threads > 1`. Instead
+    /// `offer_loop` keeps the worst-`value_efficiency` loop currently held
+    /// and evicts it for a better closing cycle. Every extension within
+    /// `max_len` is still pushed onto the heap rather than pruned against
+    /// the held set: `path_priority` closes the path as if it ended right
+    /// now, and a longer continuation can settle at a *higher*
+    /// `value_efficiency` than that naive snapshot, so it's not a sound
+    /// upper bound and can't be used to discard a path early without risking
+    /// the real best cycle along with it. Since `max_loops` doesn't bound how
+    /// much of the queue gets expanded either, `max_expansions` is the actual
+    /// wall-clock knob: once that many path extensions have been queued
+    /// across all workers, no more batches are pulled (`0` means unbounded).
+    fn find_loops_priority(
+        &self,
+        max_loops: usize,
+        max_len: usize,
+        threads: usize,
+        batch: usize,
+        dynamic_batch: bool,
+        no_stats: bool,
+        max_expansions: usize,
+    ) -> (Vec<TradeLoop>, usize) {
+        let threads = threads.max(1);
+        let n = self.trades.len();
+
+        let heap: Mutex<BinaryHeap<WorkItem>> = Mutex::new(BinaryHeap::new());
+        {
+            let mut guard = heap.lock();
+            for i in 0..n {
+                guard.push(WorkItem { path: vec![i], priority: 1.0 });
+            }
+        }
+
+        let results: Mutex<Vec<TradeLoop>> = Mutex::new(Vec::new());
+        let seen: Mutex<HashSet<Vec<usize>>> = Mutex::new(HashSet::new());
+        let expansions = AtomicUsize::new(0);
+        let total_expansions = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    if max_expansions > 0 && total_expansions.load(AtomicOrdering::Relaxed) >= max_expansions {
+                        break;
+                    }
+                    let batch_items: Vec<WorkItem> = {
+                        let mut guard = heap.lock();
+                        if guard.is_empty() {
+                            break;
+                        }
+                        let take = if dynamic_batch {
+                            (guard.len() / threads).max(1)
+                        } else {
+                            batch.max(1)
+                        };
+                        (0..take).filter_map(|_| guard.pop()).collect()
+                    };
+                    if batch_items.is_empty() {
+                        break;
+                    }
+
+                    for item in batch_items {
+                        let start = item.path[0];
+                        let last = *item.path.last().unwrap();
+                        for &w in &self.edges[last] {
+                            if w == start && item.path.len() > 1 {
+                                // Closing the cycle is always worth checking,
+                                // even once the path has hit max_len: only
+                                // further extension needs pruning.
+                                let canonical = Self::canonical_rotation(&item.path);
+                                if !seen.lock().insert(canonical) {
+                                    continue;
+                                }
+                                let loop_type = format!("{}-way", item.path.len());
+                                if let Some(loop_data) = self.create_loop_data(item.path.clone(), &loop_type) {
+                                    Self::offer_loop(&results, max_loops, loop_data);
+                                }
+                            } else if w != start
+                                && !item.path.contains(&w)
+                                && item.path.len() < max_len
+                            {
+                                let mut next_path = item.path.clone();
+                                next_path.push(w);
+                                let priority = self.path_priority(&next_path);
+                                total_expansions.fetch_add(1, AtomicOrdering::Relaxed);
+                                if !no_stats {
+                                    expansions.fetch_add(1, AtomicOrdering::Relaxed);
+                                }
+                                heap.lock().push(WorkItem { path: next_path, priority });
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut out = results.into_inner();
+        out.truncate(max_loops);
+        (out, expansions.into_inner())
+    }
+
     fn to_python(&self, py: Python) -> PyResult<PyObject> {
         let json = serde_json::to_string(&self.trades).unwrap();
         Ok(json.to_object(py))
@@ -89,95 +261,171 @@ impl TradeGraph {
 }
 
 impl TradeGraph {
+    /// Edge pre-filter for `build_from_trades`: only checks that the edge
+    /// could work in isolation. `create_loop_data` remains the authoritative
+    /// gate for cycles longer than two.
     fn is_valid_trade(&self, from: usize, to: usize) -> bool {
         let giver = &self.trades[from];
         let receiver = &self.trades[to];
-        
-        giver.have_watch != receiver.have_watch &&
-        giver.have_value >= receiver.min_acceptable_item_value &&
-        (giver.have_value - receiver.have_value) <= receiver.max_cash_top_up
+
+        giver.have_watch != receiver.have_watch
+            && solve_cash_flows(
+                &[giver.have_value, receiver.have_value],
+                &[giver.min_acceptable_item_value, receiver.min_acceptable_item_value],
+                &[giver.max_cash_top_up, receiver.max_cash_top_up],
+            )
+            .is_some()
     }
 
-    fn find_two_way_loops(&self, loops: &mut Vec<TradeLoop>, max_loops: usize) {
-        for i in 0..self.trades.len() {
-            if loops.len() >= max_loops {
-                break;
+    /// Insert `loop_data` into the shared `find_loops_priority` results if a
+    /// budget slot is free, or evict the current worst-`value_efficiency`
+    /// entry in favor of `loop_data` if it clears that bar. No-op if the
+    /// budget is full and `loop_data` doesn't beat the worst held loop.
+    fn offer_loop(results: &Mutex<Vec<TradeLoop>>, max_loops: usize, loop_data: TradeLoop) {
+        let mut res = results.lock();
+        if res.len() < max_loops {
+            res.push(loop_data);
+            return;
+        }
+        let worst = res
+            .iter()
+            .enumerate()
+            .min_by(|a, b| {
+                a.1.value_efficiency
+                    .partial_cmp(&b.1.value_efficiency)
+                    .unwrap_or(Ordering::Equal)
+            })
+            .map(|(i, l)| (i, l.value_efficiency));
+        if let Some((worst_idx, worst_efficiency)) = worst {
+            if loop_data.value_efficiency > worst_efficiency {
+                res[worst_idx] = loop_data;
             }
-            
-            for &j in &self.edges[i] {
-                if i < j && self.edges[j].contains(&i) {
-                    loops.push(self.create_loop_data(vec![i, j], "2-way"));
+        }
+    }
+
+    /// Rotate `path` so it starts at its minimum vertex, keeping the rest of
+    /// the order intact. Two rotations of the same directed cycle canonicalize
+    /// to the same vector, but unlike a full sort this still distinguishes a
+    /// cycle from its reverse (or from an unrelated cycle over the same
+    /// vertex set), since orientation is preserved.
+    fn canonical_rotation(path: &[usize]) -> Vec<usize> {
+        let min_pos = path
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &v)| v)
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        path[min_pos..].iter().chain(&path[..min_pos]).copied().collect()
+    }
+
+    /// Johnson's `circuit(v)`: DFS from `v` over the subgraph induced by
+    /// vertices `>= s`, looking for a path back to `s`. Returns whether a
+    /// cycle through `v` was found, which tells the caller whether to
+    /// `unblock(v)` or leave it blocked and record it in `B[w]`.
+    #[allow(clippy::too_many_arguments)]
+    fn circuit(
+        &self,
+        v: usize,
+        s: usize,
+        max_len: usize,
+        max_loops: usize,
+        blocked: &mut Vec<bool>,
+        b: &mut Vec<HashSet<usize>>,
+        stack: &mut Vec<usize>,
+        loops: &mut Vec<TradeLoop>,
+    ) -> bool {
+        let mut found = false;
+        stack.push(v);
+        blocked[v] = true;
+
+        for &w in &self.edges[v] {
+            if w < s {
+                continue;
+            }
+            if w == s {
+                // A cycle exists structurally whether or not it has a
+                // feasible cash assignment; `found` drives Johnson's
+                // unblocking and must reflect that regardless of whether we
+                // end up keeping the loop.
+                found = true;
+                if let Some(loop_data) = self.create_loop_data(stack.clone(), &format!("{}-way", stack.len())) {
+                    loops.push(loop_data);
                     if loops.len() >= max_loops {
                         break;
                     }
                 }
-            }
-        }
-    }
-
-    fn find_three_way_loops(&self, loops: &mut Vec<TradeLoop>, max_loops: usize) {
-        let n = self.trades.len();
-        let mut nodes: Vec<usize> = (0..n).collect();
-        let mut rng = rand::thread_rng();
-        
-        // For large graphs, use sampling
-        if n > 100 {
-            let mut attempts = 0;
-            let max_attempts = max_loops * 10;
-            
-            while loops.len() < max_loops && attempts < max_attempts {
-                nodes.shuffle(&mut rng);
-                let sample: Vec<_> = nodes.iter().take(3).copied().collect();
-                let [a, b, c] = [sample[0], sample[1], sample[2]];
-                
-                if self.edges[a].contains(&b) && 
-                   self.edges[b].contains(&c) && 
-                   self.edges[c].contains(&a) {
-                    loops.push(self.create_loop_data(vec![a, b, c], "3-way"));
+            } else if !blocked[w] && stack.len() < max_len {
+                if self.circuit(w, s, max_len, max_loops, blocked, b, stack, loops) {
+                    found = true;
                 }
-                
-                attempts += 1;
-            }
-        } else {
-            // For smaller graphs, check all possibilities
-            for &i in &nodes {
                 if loops.len() >= max_loops {
                     break;
                 }
-                
-                for &j in &self.edges[i] {
-                    if j <= i { continue; }
-                    
-                    for &k in &self.edges[j] {
-                        if k <= j { continue; }
-                        
-                        if self.edges[k].contains(&i) {
-                            loops.push(self.create_loop_data(vec![i, j, k], "3-way"));
-                            if loops.len() >= max_loops {
-                                break;
-                            }
-                        }
-                    }
+            }
+        }
+
+        if found {
+            self.unblock(v, blocked, b);
+        } else {
+            for &w in &self.edges[v] {
+                if w >= s {
+                    b[w].insert(v);
                 }
             }
         }
+
+        stack.pop();
+        found
+    }
+
+    fn unblock(&self, v: usize, blocked: &mut Vec<bool>, b: &mut Vec<HashSet<usize>>) {
+        blocked[v] = false;
+        let dependents: Vec<usize> = b[v].drain().collect();
+        for w in dependents {
+            if blocked[w] {
+                self.unblock(w, blocked, b);
+            }
+        }
+    }
+
+    /// Heuristic ordering for the work-queue: the `value_efficiency` the
+    /// path would have if it closed back to its origin right now. This is
+    /// not an upper bound on what a longer continuation could achieve --
+    /// further hops can raise the real efficiency past this snapshot -- so
+    /// it's only ever used to pick which path to expand next, never to
+    /// discard one.
+    fn path_priority(&self, path: &[usize]) -> f64 {
+        let n = path.len();
+        if n < 2 {
+            return 1.0;
+        }
+        let values: Vec<f64> = path.iter().map(|&i| self.trades[i].have_value).collect();
+        let cash_flows: Vec<f64> = (0..n).map(|i| values[i] - values[(i + 1) % n]).collect();
+        let total_watch_value: f64 = values.iter().sum();
+        let total_cash_flow: f64 = cash_flows.iter().map(|x| x.abs()).sum();
+        total_watch_value / (total_watch_value + total_cash_flow)
     }
 
-    fn create_loop_data(&self, indexes: Vec<usize>, loop_type: &str) -> TradeLoop {
-        let n = indexes.len();
+    /// Builds the `TradeLoop` for a cycle, or `None` if no feasible cash
+    /// assignment exists (see `solve_cash_flows`) and the cycle has to be
+    /// dropped.
+    fn create_loop_data(&self, indexes: Vec<usize>, loop_type: &str) -> Option<TradeLoop> {
         let users: Vec<_> = indexes.iter().map(|&i| self.trades[i].user_id.clone()).collect();
         let watches: Vec<_> = indexes.iter().map(|&i| self.trades[i].have_watch.clone()).collect();
         let values: Vec<_> = indexes.iter().map(|&i| self.trades[i].have_value).collect();
-        
-        let cash_flows: Vec<_> = (0..n)
-            .map(|i| values[i] - values[(i + 1) % n])
+        let min_acceptable: Vec<_> = indexes
+            .iter()
+            .map(|&i| self.trades[i].min_acceptable_item_value)
             .collect();
-        
+        let max_top_up: Vec<_> = indexes.iter().map(|&i| self.trades[i].max_cash_top_up).collect();
+
+        let cash_flows = solve_cash_flows(&values, &min_acceptable, &max_top_up)?;
+
         let total_watch_value: f64 = values.iter().sum();
         let total_cash_flow: f64 = cash_flows.iter().map(|x| x.abs()).sum();
         let value_efficiency = total_watch_value / (total_watch_value + total_cash_flow);
 
-        TradeLoop {
+        Some(TradeLoop {
             loop_type: loop_type.to_string(),
             indexes,
             users,
@@ -187,6 +435,405 @@ impl TradeGraph {
             total_watch_value,
             total_cash_flow,
             value_efficiency
+        })
+    }
+}
+
+/// Solve for a feasible set of net cash transfers around a trade cycle.
+///
+/// Each user at position `i` gives up their own watch and receives the
+/// watch at position `(i + 1) % n`, i.e. `received_value[i] = values[(i +
+/// 1) % n]`. Their net payment `c_i` (positive: pays cash, negative:
+/// receives cash) must satisfy the closed-loop conservation constraint
+/// `sum(c_i) == 0`, the budget constraint `c_i <= max_cash_top_up[i]` when
+/// they're a net payer, and the acceptance constraint that what they end up
+/// with, `received_value[i] - c_i`, is no worse than `min_acceptable[i]`.
+/// Both of the latter collapse into a single per-user upper bound on `c_i`;
+/// the cycle is feasible iff those bounds can still sum to zero. Among
+/// feasible assignments this picks the one minimizing total absolute cash
+/// movement, splitting the required payments proportionally across users
+/// who have room to pay. Returns `None` if no feasible assignment exists.
+fn solve_cash_flows(values: &[f64], min_acceptable: &[f64], max_top_up: &[f64]) -> Option<Vec<f64>> {
+    let n = values.len();
+    let upper: Vec<f64> = (0..n)
+        .map(|i| {
+            let received = values[(i + 1) % n];
+            max_top_up[i].min(received - min_acceptable[i])
+        })
+        .collect();
+
+    let deficit: f64 = upper.iter().filter(|&&u| u < 0.0).map(|u| -u).sum();
+    let capacity: f64 = upper.iter().filter(|&&u| u >= 0.0).sum();
+
+    const EPS: f64 = 1e-9;
+    if capacity + EPS < deficit {
+        return None;
+    }
+
+    let mut cash_flows = vec![0.0; n];
+    for i in 0..n {
+        if upper[i] < 0.0 {
+            cash_flows[i] = upper[i];
+        }
+    }
+    if deficit > EPS {
+        let ratio = if capacity > EPS { deficit / capacity } else { 0.0 };
+        for i in 0..n {
+            if upper[i] >= 0.0 {
+                cash_flows[i] = upper[i] * ratio;
+            }
+        }
+    }
+
+    Some(cash_flows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_cycle_trades() -> Vec<Trade> {
+        vec![
+            Trade {
+                user_id: "user1".to_string(),
+                have_watch: "watchA".to_string(),
+                have_value: 1000.0,
+                min_acceptable_item_value: 0.0,
+                max_cash_top_up: 100.0,
+            },
+            Trade {
+                user_id: "user2".to_string(),
+                have_watch: "watchB".to_string(),
+                have_value: 1000.0,
+                min_acceptable_item_value: 0.0,
+                max_cash_top_up: 100.0,
+            },
+        ]
+    }
+
+    /// A graph over `n` trades, all mutually cash-feasible (equal value,
+    /// no floor, ample top-up) so only the explicit `edges` given determine
+    /// which cycles exist, not `is_valid_trade`'s own feasibility check.
+    fn graph_with_edges(n: usize, edges: Vec<Vec<usize>>) -> TradeGraph {
+        TradeGraph {
+            trades: (0..n)
+                .map(|i| Trade {
+                    user_id: format!("user{i}"),
+                    have_watch: format!("watch{i}"),
+                    have_value: 1000.0,
+                    min_acceptable_item_value: 0.0,
+                    max_cash_top_up: 100.0,
+                })
+                .collect(),
+            edges,
+        }
+    }
+
+    fn sorted_user_sets(loops: &[TradeLoop]) -> Vec<Vec<String>> {
+        let mut sets: Vec<Vec<String>> = loops
+            .iter()
+            .map(|l| {
+                let mut users = l.users.clone();
+                users.sort();
+                users
+            })
+            .collect();
+        sets.sort();
+        sets
+    }
+
+    #[test]
+    fn find_loops_enumerates_two_triangles_sharing_a_vertex() {
+        // 0 -> 1 -> 2 -> 0, and 2 -> 3 -> 4 -> 2, sharing only vertex 2.
+        let graph = graph_with_edges(
+            5,
+            vec![vec![1], vec![2], vec![0, 3], vec![4], vec![2]],
+        );
+
+        let loops = graph.find_loops(10, 3);
+        assert_eq!(loops.len(), 2);
+        assert_eq!(
+            sorted_user_sets(&loops),
+            vec![
+                vec!["user0".to_string(), "user1".to_string(), "user2".to_string()],
+                vec!["user2".to_string(), "user3".to_string(), "user4".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn find_loops_enumerates_two_triangles_sharing_an_edge() {
+        // 0 -> 1 -> 2 -> 0, and 1 -> 2 -> 3 -> 1, sharing the edge 1 -> 2.
+        let graph = graph_with_edges(4, vec![vec![1], vec![2], vec![0, 3], vec![1]]);
+
+        let loops = graph.find_loops(10, 3);
+        assert_eq!(loops.len(), 2);
+        assert_eq!(
+            sorted_user_sets(&loops),
+            vec![
+                vec!["user0".to_string(), "user1".to_string(), "user2".to_string()],
+                vec!["user1".to_string(), "user2".to_string(), "user3".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn find_loops_respects_max_len_boundary() {
+        // A single triangle: max_len == 3 must close it, max_len == 2 must
+        // not, since a 3-way cycle can't fit in a path capped at length 2.
+        let graph = graph_with_edges(3, vec![vec![1], vec![2], vec![0]]);
+
+        assert_eq!(graph.find_loops(10, 3).len(), 1);
+        assert_eq!(graph.find_loops(10, 2).len(), 0);
+    }
+
+    #[test]
+    fn build_from_trades_wires_up_a_giver_side_cash_top_up_edge() {
+        // user0's item alone (400) falls short of user1's floor (500), but
+        // user0 can top up $100 in cash to clear it -- the same pair
+        // `solve_cash_flows` would accept as a 2-cycle, so the edge must
+        // still be built rather than dropped by a narrower pre-filter.
+        let trades = vec![
+            Trade {
+                user_id: "user0".to_string(),
+                have_watch: "watchA".to_string(),
+                have_value: 400.0,
+                min_acceptable_item_value: 0.0,
+                max_cash_top_up: 200.0,
+            },
+            Trade {
+                user_id: "user1".to_string(),
+                have_watch: "watchB".to_string(),
+                have_value: 300.0,
+                min_acceptable_item_value: 500.0,
+                max_cash_top_up: 0.0,
+            },
+        ];
+        let mut graph = TradeGraph::new();
+        graph.build_from_trades(trades);
+
+        assert_eq!(graph.find_loops(10, 2).len(), 1);
+    }
+
+    #[test]
+    fn find_loops_priority_finds_loop_at_exact_max_len() {
+        let mut graph = TradeGraph::new();
+        graph.build_from_trades(two_cycle_trades());
+
+        // max_len == the cycle's own length must be enough to close it: a
+        // path is only barred from *extending* past max_len, not from
+        // closing back to its start. Before the fix this returned 0 loops.
+        let (exact, _) = graph.find_loops_priority(10, 2, 1, 10, false, true, 0);
+        assert!(
+            !exact.is_empty(),
+            "expected the 2-cycle to close at max_len == 2"
+        );
+
+        // find_loops (Johnson's) canonicalizes each cycle to a single
+        // rotation, so it reports exactly one; find_loops_priority seeds a
+        // search from every vertex and would otherwise report the same
+        // cycle once per rotation, but the seen-set collapses those down to
+        // the one distinct cycle too.
+        assert_eq!(exact.len(), 1);
+        let johnsons = graph.find_loops(10, 2);
+        assert_eq!(johnsons.len(), 1);
+    }
+
+    #[test]
+    fn find_loops_priority_does_not_burn_the_budget_on_rotations_of_one_cycle() {
+        // A single directed triangle: 0 -> 1 -> 2 -> 0, and nothing else.
+        // Built directly rather than via `build_from_trades`, which would
+        // also wire up the reverse edges (equal-value trades are feasible
+        // both ways) and introduce parasitic 2-cycles alongside it. With no
+        // dedup, seeding a search from every vertex finds this one triangle
+        // three times (once per starting vertex), which would consume a
+        // tight max_loops budget on copies of one opportunity instead of
+        // distinct ones.
+        let graph = TradeGraph {
+            trades: (0..3)
+                .map(|i| Trade {
+                    user_id: format!("u{i}"),
+                    have_watch: format!("w{i}"),
+                    have_value: 1000.0,
+                    min_acceptable_item_value: 0.0,
+                    max_cash_top_up: 100.0,
+                })
+                .collect(),
+            edges: vec![vec![1], vec![2], vec![0]],
+        };
+
+        let (loops, _) = graph.find_loops_priority(2, 3, 1, 10, false, true, 0);
+        assert_eq!(loops.len(), 1, "expected the triangle's rotations to collapse to a single distinct loop");
+    }
+
+    #[test]
+    fn find_loops_priority_keeps_opposite_direction_cycles_distinct() {
+        // Both 0 -> 1 -> 2 -> 0 and 0 -> 2 -> 1 -> 0 are wired up: the same
+        // three vertices, two genuinely different cycles. Canonicalizing on
+        // a sorted vertex set would collapse them into one; canonicalizing
+        // on a rotation must keep them separate, matching Johnson's.
+        let graph = graph_with_edges(3, vec![vec![1, 2], vec![2, 0], vec![0, 1]]);
+
+        let johnsons = graph.find_loops(10, 3);
+        let (priority, _) = graph.find_loops_priority(10, 3, 1, 10, false, true, 0);
+        assert_eq!(priority.len(), johnsons.len());
+    }
+
+    #[test]
+    fn find_loops_priority_keeps_the_best_loop_under_concurrency() {
+        // Two disjoint 2-cycles: {0,1} trade at equal value (cash-free,
+        // value_efficiency == 1.0) and {2,3} trade at unequal value with a
+        // floor that forces a cash top-up (value_efficiency < 1.0). With
+        // max_loops == 1, a first-come-first-served accept would keep
+        // whichever cycle's worker happened to close first; `offer_loop`
+        // must instead evict the worse one so the perfect-efficiency cycle
+        // always wins the single slot, regardless of how many workers race
+        // to close a cycle first.
+        let graph = TradeGraph {
+            trades: vec![
+                Trade {
+                    user_id: "u0".to_string(),
+                    have_watch: "w0".to_string(),
+                    have_value: 1000.0,
+                    min_acceptable_item_value: 0.0,
+                    max_cash_top_up: 0.0,
+                },
+                Trade {
+                    user_id: "u1".to_string(),
+                    have_watch: "w1".to_string(),
+                    have_value: 1000.0,
+                    min_acceptable_item_value: 0.0,
+                    max_cash_top_up: 0.0,
+                },
+                Trade {
+                    user_id: "u2".to_string(),
+                    have_watch: "w2".to_string(),
+                    have_value: 1000.0,
+                    min_acceptable_item_value: 0.0,
+                    max_cash_top_up: 500.0,
+                },
+                Trade {
+                    user_id: "u3".to_string(),
+                    have_watch: "w3".to_string(),
+                    have_value: 500.0,
+                    min_acceptable_item_value: 1200.0,
+                    max_cash_top_up: 500.0,
+                },
+            ],
+            edges: vec![vec![1], vec![0], vec![3], vec![2]],
+        };
+
+        for (threads, dynamic_batch) in [(4, false), (4, true)] {
+            let (loops, _) = graph.find_loops_priority(1, 2, threads, 1, dynamic_batch, true, 0);
+            assert_eq!(loops.len(), 1, "threads={threads} dynamic_batch={dynamic_batch}");
+            assert_eq!(
+                loops[0].users,
+                vec!["u0".to_string(), "u1".to_string()],
+                "the perfect-efficiency cycle must win the single slot (threads={threads}, dynamic_batch={dynamic_batch})"
+            );
+        }
+    }
+
+    #[test]
+    fn find_loops_priority_reports_expansions_only_when_stats_are_requested() {
+        let mut graph = TradeGraph::new();
+        graph.build_from_trades(two_cycle_trades());
+
+        let (_, with_stats) = graph.find_loops_priority(10, 2, 1, 10, false, false, 0);
+        assert!(with_stats > 0, "expected at least one path extension to be counted");
+
+        let (_, without_stats) = graph.find_loops_priority(10, 2, 1, 10, false, true, 0);
+        assert_eq!(without_stats, 0, "no_stats should skip the expansion counter entirely");
+    }
+
+    #[test]
+    fn find_loops_priority_max_expansions_bounds_the_work_done() {
+        // A dense complete digraph with a generous max_len has a huge space
+        // of partial paths to expand; without a cap this would run far
+        // longer than `find_loops` ever needs to for the same `max_loops`.
+        let n = 6;
+        let edges: Vec<Vec<usize>> = (0..n).map(|i| (0..n).filter(|&j| j != i).collect()).collect();
+        let graph = graph_with_edges(n, edges);
+
+        let (_, uncapped) = graph.find_loops_priority(10, n, 1, 1, false, false, 0);
+        let (_, capped) = graph.find_loops_priority(10, n, 1, 1, false, false, 5);
+
+        assert!(
+            capped <= 10,
+            "max_expansions=5 should keep total expansions small, got {capped}"
+        );
+        assert!(
+            capped < uncapped,
+            "capped run ({capped}) should expand far less than the uncapped run ({uncapped})"
+        );
+    }
+
+    #[test]
+    fn find_loops_priority_does_not_drop_a_real_cycle_behind_a_pessimistic_prefix() {
+        // A triangle (0-1-2) whose legs have wildly skewed values -- so every
+        // 2-length prefix's naive `path_priority` (computed as if it closed
+        // right there) is far below 0.9 -- but whose `min_acceptable_item_value`
+        // of 0 on all three legs means the real, fully-closed cycle settles
+        // with zero cash and a true `value_efficiency` of 1.0. Alongside it,
+        // three disjoint 2-cycles that each genuinely settle at 0.9 and close
+        // in a single hop, so their real priority beats every prefix of the
+        // triangle.
+        //
+        // A bound derived from an unclosed prefix isn't admissible: extending
+        // a path can raise its real efficiency past what a premature close
+        // would report, so it must never be used to discard that prefix.
+        // With `max_loops` exactly sized to the three distractors, a search
+        // that prunes on the naive prefix priority fills the budget with the
+        // 0.9 loops and starves every extension toward the 1.0 triangle
+        // before it can close -- dropping the strictly better cycle entirely.
+        fn cycle_leg(user: &str, watch: &str, value: f64) -> Trade {
+            Trade {
+                user_id: user.to_string(),
+                have_watch: watch.to_string(),
+                have_value: value,
+                min_acceptable_item_value: 0.0,
+                max_cash_top_up: 0.0,
+            }
         }
+
+        let mut trades = vec![
+            cycle_leg("a", "wa", 100.0),
+            cycle_leg("b", "wb", 900.0),
+            cycle_leg("c", "wc", 500.0),
+        ];
+        let mut edges = vec![vec![1], vec![2], vec![0]];
+
+        // Three distractor 2-cycles, each forced to a real value_efficiency
+        // of exactly 0.9 by a min_acceptable floor that's unreachable without
+        // a cash top-up (see `build_from_trades_wires_up_a_giver_side_cash_top_up_edge`).
+        for i in 0..3 {
+            let giver = trades.len();
+            trades.push(Trade {
+                user_id: format!("d{i}-giver"),
+                have_watch: format!("d{i}-watchA"),
+                have_value: 900.0,
+                min_acceptable_item_value: 0.0,
+                max_cash_top_up: 150.0,
+            });
+            trades.push(Trade {
+                user_id: format!("d{i}-receiver"),
+                have_watch: format!("d{i}-watchB"),
+                have_value: 900.0,
+                min_acceptable_item_value: 1000.0,
+                max_cash_top_up: 50.0,
+            });
+            edges.push(vec![giver + 1]);
+            edges.push(vec![giver]);
+        }
+
+        let graph = TradeGraph { trades, edges };
+
+        let (loops, _) = graph.find_loops_priority(3, 3, 1, 50, false, true, 0);
+        assert_eq!(loops.len(), 3);
+        assert!(
+            loops.iter().any(|l| l.value_efficiency > 0.99),
+            "the perfect-efficiency triangle must not be pruned away by the 0.9 distractors: got {:?}",
+            loops.iter().map(|l| l.value_efficiency).collect::<Vec<_>>()
+        );
     }
 } 
\ No newline at end of file
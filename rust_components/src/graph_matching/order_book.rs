@@ -0,0 +1,450 @@
+use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use super::{Trade, TradeLoop};
+
+/// A standing bid or ask resting in the book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Order {
+    pub order_id: u64,
+    pub user_id: String,
+    pub have_watch: String,
+    pub have_value: f64,
+    pub want_model: String,
+    pub min_acceptable_value: f64,
+    pub max_cash_top_up: f64,
+    pub quantity: u32,
+}
+
+/// Orders asks by lowest `min_acceptable_value`, earliest first on ties.
+#[derive(Debug, Clone)]
+struct AskEntry {
+    order: Order,
+    sequence: u64,
+}
+
+impl PartialEq for AskEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.order.min_acceptable_value == other.order.min_acceptable_value
+            && self.sequence == other.sequence
+    }
+}
+impl Eq for AskEntry {}
+
+impl PartialOrd for AskEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AskEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // BinaryHeap is a max-heap; reverse price so the lowest ask is popped
+        // first, then break ties in favor of the order that arrived first.
+        other
+            .order
+            .min_acceptable_value
+            .partial_cmp(&self.order.min_acceptable_value)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Orders bids by highest `have_value`, earliest first on ties.
+#[derive(Debug, Clone)]
+struct BidEntry {
+    order: Order,
+    sequence: u64,
+}
+
+impl PartialEq for BidEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.order.have_value == other.order.have_value && self.sequence == other.sequence
+    }
+}
+impl Eq for BidEntry {}
+
+impl PartialOrd for BidEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BidEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order
+            .have_value
+            .partial_cmp(&other.order.have_value)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Continuous-clearing complement to `TradeGraph`: bids/asks rest here and
+/// `match_orders` clears them price-time-priority as they cross.
+#[pyclass]
+pub struct OrderBook {
+    // Asks are keyed by the watch model being offered; bids are keyed by the
+    // watch model the bidder wants to acquire.
+    asks: HashMap<String, BinaryHeap<AskEntry>>,
+    bids: HashMap<String, BinaryHeap<BidEntry>>,
+    next_sequence: u64,
+    filled: Vec<Trade>,
+}
+
+#[pymethods]
+impl OrderBook {
+    #[new]
+    fn new() -> Self {
+        OrderBook {
+            asks: HashMap::new(),
+            bids: HashMap::new(),
+            next_sequence: 0,
+            filled: Vec::new(),
+        }
+    }
+
+    fn add_ask(&mut self, order: Order) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.asks
+            .entry(order.have_watch.clone())
+            .or_default()
+            .push(AskEntry { order, sequence });
+    }
+
+    fn add_bid(&mut self, order: Order) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.bids
+            .entry(order.want_model.clone())
+            .or_default()
+            .push(BidEntry { order, sequence });
+    }
+
+    /// Clear crossing bid/ask pairs for each watch model, filling as much
+    /// quantity as both sides can bear and leaving any residual quantity
+    /// resting back on the book. The heaps are only price-ordered, so every
+    /// resting ask is checked against every resting bid rather than trusting
+    /// the heap tops to be the match.
+    fn match_orders(&mut self) -> Vec<TradeLoop> {
+        let mut fills = Vec::new();
+
+        let models: Vec<String> = self
+            .bids
+            .keys()
+            .filter(|m| self.asks.contains_key(*m))
+            .cloned()
+            .collect();
+
+        for model in models {
+            // Pop both sides out of their heaps once; `.into_sorted_vec()`
+            // is ascending, so reverse it to iterate best-price-first.
+            let mut asks: Vec<AskEntry> = self.asks.remove(&model).unwrap_or_default().into_sorted_vec();
+            asks.reverse();
+            let mut bids: Vec<BidEntry> = self.bids.remove(&model).unwrap_or_default().into_sorted_vec();
+            bids.reverse();
+
+            loop {
+                let mut matched = None;
+                'search: for (ai, ask) in asks.iter().enumerate() {
+                    for (bi, bid) in bids.iter().enumerate() {
+                        let fill_qty = ask.order.quantity.min(bid.order.quantity);
+                        if !Self::crosses(&ask.order, &bid.order, fill_qty) {
+                            continue;
+                        }
+                        if let Some((trade_loop, ask_trade, bid_trade)) =
+                            Self::settle(&ask.order, &bid.order, fill_qty)
+                        {
+                            matched = Some((ai, bi, fill_qty, trade_loop, ask_trade, bid_trade));
+                            break 'search;
+                        }
+                    }
+                }
+
+                let (ai, bi, fill_qty, trade_loop, ask_trade, bid_trade) = match matched {
+                    Some(m) => m,
+                    None => break,
+                };
+
+                fills.push(trade_loop);
+                // `Trade` has no quantity field, so one unit-sized pair per
+                // unit filled, not a single pair for the whole fill.
+                for _ in 0..fill_qty {
+                    self.filled.push(ask_trade.clone());
+                    self.filled.push(bid_trade.clone());
+                }
+
+                // Prorate the per-order floor/budget by what this fill used,
+                // since residual quantity is checked against them again on
+                // the next fill (see `settle`). Net held value is
+                // `received - cash_flows[i]` (positive means paid cash).
+                let ask_net_received = trade_loop.values[1] - trade_loop.cash_flows[0];
+                let ask_cash_paid = trade_loop.cash_flows[0].max(0.0);
+                asks[ai].order.min_acceptable_value =
+                    (asks[ai].order.min_acceptable_value - ask_net_received).max(0.0);
+                asks[ai].order.max_cash_top_up =
+                    (asks[ai].order.max_cash_top_up - ask_cash_paid).max(0.0);
+
+                let bid_net_received = trade_loop.values[0] - trade_loop.cash_flows[1];
+                let bid_cash_paid = trade_loop.cash_flows[1].max(0.0);
+                bids[bi].order.min_acceptable_value =
+                    (bids[bi].order.min_acceptable_value - bid_net_received).max(0.0);
+                bids[bi].order.max_cash_top_up =
+                    (bids[bi].order.max_cash_top_up - bid_cash_paid).max(0.0);
+
+                asks[ai].order.quantity -= fill_qty;
+                bids[bi].order.quantity -= fill_qty;
+                if asks[ai].order.quantity == 0 {
+                    asks.remove(ai);
+                }
+                if bids[bi].order.quantity == 0 {
+                    bids.remove(bi);
+                }
+            }
+
+            if !asks.is_empty() {
+                self.asks.insert(model.clone(), asks.into_iter().collect());
+            }
+            if !bids.is_empty() {
+                self.bids.insert(model, bids.into_iter().collect());
+            }
+        }
+
+        fills
+    }
+
+    /// Drain the `Trade` legs produced by fills so far, for feeding back into
+    /// `TradeGraph::build_from_trades`.
+    fn drain_filled_trades(&mut self) -> Vec<Trade> {
+        std::mem::take(&mut self.filled)
+    }
+}
+
+impl OrderBook {
+    /// Pre-filter ahead of `settle`'s full solve. `fill_qty` must match the
+    /// quantity `settle` would be called with: the floor/top-up are
+    /// per-order, not per-unit, so checking at unit scale can give the wrong
+    /// answer for a multi-unit fill.
+    fn crosses(ask: &Order, bid: &Order, fill_qty: u32) -> bool {
+        let qty = fill_qty as f64;
+        super::solve_cash_flows(
+            &[ask.have_value * qty, bid.have_value * qty],
+            &[ask.min_acceptable_value, bid.min_acceptable_value],
+            &[ask.max_cash_top_up, bid.max_cash_top_up],
+        )
+        .is_some()
+    }
+
+    /// Settle a crossing ask/bid pair filling `fill_qty` units, or `None` if
+    /// no feasible cash assignment exists. `min_acceptable_value`/
+    /// `max_cash_top_up` are per order, not per unit, so `have_value` is
+    /// scaled up to the whole fill before the solve rather than solving
+    /// per-unit and scaling the result.
+    fn settle(ask: &Order, bid: &Order, fill_qty: u32) -> Option<(TradeLoop, Trade, Trade)> {
+        let qty = fill_qty as f64;
+        let values = vec![ask.have_value * qty, bid.have_value * qty];
+        let min_acceptable = vec![ask.min_acceptable_value, bid.min_acceptable_value];
+        let max_top_up = vec![ask.max_cash_top_up, bid.max_cash_top_up];
+        let cash_flows = super::solve_cash_flows(&values, &min_acceptable, &max_top_up)?;
+
+        let total_watch_value: f64 = values.iter().sum();
+        let total_cash_flow: f64 = cash_flows.iter().map(|x| x.abs()).sum();
+        let value_efficiency = total_watch_value / (total_watch_value + total_cash_flow);
+
+        let trade_loop = TradeLoop {
+            loop_type: "order-fill".to_string(),
+            indexes: vec![0, 1],
+            users: vec![ask.user_id.clone(), bid.user_id.clone()],
+            watches: vec![ask.have_watch.clone(), bid.have_watch.clone()],
+            values,
+            cash_flows,
+            total_watch_value,
+            total_cash_flow,
+            value_efficiency,
+        };
+
+        let ask_trade = Trade {
+            user_id: ask.user_id.clone(),
+            have_watch: bid.have_watch.clone(),
+            have_value: bid.have_value,
+            min_acceptable_item_value: ask.min_acceptable_value,
+            max_cash_top_up: ask.max_cash_top_up,
+        };
+        let bid_trade = Trade {
+            user_id: bid.user_id.clone(),
+            have_watch: ask.have_watch.clone(),
+            have_value: ask.have_value,
+            min_acceptable_item_value: bid.min_acceptable_value,
+            max_cash_top_up: bid.max_cash_top_up,
+        };
+
+        Some((trade_loop, ask_trade, bid_trade))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(order_id: u64, user_id: &str, have_watch: &str, have_value: f64, want_model: &str, quantity: u32) -> Order {
+        Order {
+            order_id,
+            user_id: user_id.to_string(),
+            have_watch: have_watch.to_string(),
+            have_value,
+            want_model: want_model.to_string(),
+            min_acceptable_value: 500.0,
+            max_cash_top_up: 0.0,
+            quantity,
+        }
+    }
+
+    #[test]
+    fn match_orders_scales_fill_by_quantity_actually_filled() {
+        let mut book = OrderBook::new();
+        book.add_ask(order(1, "seller", "watchA", 1000.0, "watchA", 5));
+        book.add_bid(order(2, "buyer", "watchA", 1000.0, "watchA", 3));
+
+        let fills = book.match_orders();
+
+        assert_eq!(fills.len(), 1);
+        let fill = &fills[0];
+        // 3 units filled at 1000 value each side, not a single unit's worth.
+        assert_eq!(fill.total_watch_value, 6000.0);
+
+        // The residual 2 units of the ask should still be resting: a fresh
+        // bid for exactly that quantity should fill in full.
+        book.add_bid(order(3, "buyer2", "watchA", 1000.0, "watchA", 2));
+        let more_fills = book.match_orders();
+        assert_eq!(more_fills.len(), 1);
+        assert_eq!(more_fills[0].total_watch_value, 4000.0);
+    }
+
+    #[test]
+    fn match_orders_does_not_stop_at_the_first_non_crossing_pair() {
+        let mut book = OrderBook::new();
+        // Best-by-heap-order ask (lowest floor) does not cross the bid...
+        book.add_ask(order(1, "seller1", "watchA", 5000.0, "watchA", 1));
+        // ...but a worse-by-heap-order ask does.
+        let mut cheap_ask = order(2, "seller2", "watchA", 1000.0, "watchA", 1);
+        cheap_ask.min_acceptable_value = 200.0;
+        book.asks
+            .get_mut("watchA")
+            .unwrap()
+            .push(AskEntry { order: cheap_ask, sequence: 1 });
+
+        let mut bid = order(3, "buyer", "watchA", 1000.0, "watchA", 1);
+        bid.min_acceptable_value = 500.0;
+        book.add_bid(bid);
+
+        let fills = book.match_orders();
+        assert_eq!(fills.len(), 1, "the crossing pair should still be found even though it's not the heap top");
+    }
+
+    #[test]
+    fn crosses_admits_an_ask_side_cash_top_up() {
+        // The ask's item alone (400) falls short of the bid's floor (500),
+        // but the ask can top up $100 in cash to clear it -- a crossing
+        // `settle` would accept, so `crosses` must not reject it first.
+        let mut ask = order(1, "seller", "watchA", 400.0, "watchA", 1);
+        ask.min_acceptable_value = 0.0;
+        ask.max_cash_top_up = 200.0;
+        let mut bid = order(2, "buyer", "watchA", 300.0, "watchA", 1);
+        bid.min_acceptable_value = 500.0;
+        bid.max_cash_top_up = 0.0;
+
+        assert!(OrderBook::settle(&ask, &bid, 1).is_some());
+        assert!(OrderBook::crosses(&ask, &bid, 1));
+    }
+
+    #[test]
+    fn crosses_evaluates_feasibility_at_the_candidate_fill_quantity() {
+        // Unit-scale: ask receives 100 < its bid's 250 floor -- infeasible.
+        // At fill_qty=3 the ask receives 300, clearing the floor with no
+        // cash needed, so `crosses` must say yes at qty=3 even though it
+        // would say no at qty=1.
+        let mut ask = order(1, "seller", "watchA", 100.0, "watchA", 3);
+        ask.min_acceptable_value = 0.0;
+        ask.max_cash_top_up = 0.0;
+        let mut bid = order(2, "buyer", "watchA", 100.0, "watchA", 3);
+        bid.min_acceptable_value = 250.0;
+        bid.max_cash_top_up = 0.0;
+
+        assert!(!OrderBook::crosses(&ask, &bid, 1));
+        assert!(OrderBook::crosses(&ask, &bid, 3));
+        assert!(OrderBook::settle(&ask, &bid, 3).is_some());
+    }
+
+    #[test]
+    fn settle_respects_max_cash_top_up_for_multi_unit_fills() {
+        // Same orders as `crosses_admits_an_ask_side_cash_top_up`, but filled
+        // 3 units at once. `max_cash_top_up`/`min_acceptable_value` are per
+        // order, not per unit, so the ask must never be committed to paying
+        // more than its declared $200 top-up regardless of `fill_qty`.
+        let mut ask = order(1, "seller", "watchA", 400.0, "watchA", 3);
+        ask.min_acceptable_value = 0.0;
+        ask.max_cash_top_up = 200.0;
+        let mut bid = order(2, "buyer", "watchA", 300.0, "watchA", 3);
+        bid.min_acceptable_value = 500.0;
+        bid.max_cash_top_up = 0.0;
+
+        let (trade_loop, _, _) = OrderBook::settle(&ask, &bid, 3).expect("feasible fill");
+        assert!(
+            trade_loop.cash_flows[0].abs() <= ask.max_cash_top_up + 1e-9,
+            "ask side paid {} cash, more than its {} top-up budget",
+            trade_loop.cash_flows[0].abs(),
+            ask.max_cash_top_up
+        );
+    }
+
+    #[test]
+    fn drain_filled_trades_reflects_the_full_filled_quantity() {
+        let mut book = OrderBook::new();
+        book.add_ask(order(1, "seller", "watchA", 1000.0, "watchA", 5));
+        book.add_bid(order(2, "buyer", "watchA", 1000.0, "watchA", 3));
+
+        let fills = book.match_orders();
+        assert_eq!(fills.len(), 1);
+
+        // 3 units filled, so 3 post-trade holdings per side should be fed
+        // back, not just one.
+        let trades = book.drain_filled_trades();
+        assert_eq!(trades.len(), 6);
+        assert_eq!(trades.iter().filter(|t| t.user_id == "seller").count(), 3);
+        assert_eq!(trades.iter().filter(|t| t.user_id == "buyer").count(), 3);
+    }
+
+    #[test]
+    fn partial_fills_prorate_the_per_order_cash_top_up_budget() {
+        // The ask's item alone (400) falls short of the bid's floor (500)
+        // per unit, so each unit needs a $100 cash top-up from the ask side;
+        // the ask only authorized $150 total, enough for one unit's top-up
+        // but not two.
+        let mut ask = order(1, "seller", "watchA", 400.0, "watchA", 2);
+        ask.min_acceptable_value = 0.0;
+        ask.max_cash_top_up = 150.0;
+
+        let mut bid1 = order(2, "buyer1", "watchA", 300.0, "watchA", 1);
+        bid1.min_acceptable_value = 500.0;
+        bid1.max_cash_top_up = 0.0;
+        let mut bid2 = order(3, "buyer2", "watchA", 300.0, "watchA", 1);
+        bid2.min_acceptable_value = 500.0;
+        bid2.max_cash_top_up = 0.0;
+
+        let mut book = OrderBook::new();
+        book.add_ask(ask);
+        book.add_bid(bid1);
+        book.add_bid(bid2);
+
+        let fills = book.match_orders();
+        // The first unit consumes the ask's whole $150 budget as a $100
+        // top-up (leaving $50 of now-unusable slack); the second unit would
+        // need another $100 top-up the ask no longer has authorized, so it
+        // should be left resting rather than silently overspending.
+        assert_eq!(fills.len(), 1, "only one of the two units should clear");
+        assert!(fills[0].cash_flows[0].abs() <= 150.0 + 1e-9);
+    }
+}
@@ -10,8 +10,10 @@ use pyo3::prelude::*;
 #[pymodule]
 fn chariot_engine_core(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<graph_matching::TradeGraph>()?;
+    m.add_class::<graph_matching::OrderBook>()?;
     m.add_class::<validation::TradeValidator>()?;
     m.add_class::<trade_simulation::TradeSimulator>()?;
     m.add_class::<user_generation::UserGenerator>()?;
+    m.add_function(wrap_pyfunction!(graph_matching::clear_loops, m)?)?;
     Ok(())
 } 
\ No newline at end of file